@@ -3,22 +3,29 @@
 use std::ops::{Deref, DerefMut};
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use chrono::prelude::*;
+use futures::executor;
 use futures::future::{self, Either};
 use futures::prelude::*;
 use futures::stream;
 use futures::sync::mpsc::{self, Sender, Receiver};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use globset::GlobSet;
 use memmap::{Mmap, Protection};
 
 use {BATCH_FUTURE_BUFFER_SIZE, WRITE_FUTURE_BUFFER_SIZE};
 use arc_slice::{self, ArcSlice};
+use crypto::Cipher;
 use errors::*;
 use index::Cached;
+use job::{CancelToken, CommitHandle, CommitProgress};
 use marshal::{ObjectHash, Marshaller, Hashed, CommitObject, DirTree};
+use merkle::{MerkleHash, MerkleProof, MerkleTree, MERKLE_HASH_SIZE};
 use repository::Repository;
+use scheduler::{FileHandler, MarshalHandler, Operation, Priority, Scheduler};
 use split::SliceChunker;
 use store::{Store, Empty};
 use trace::Trace;
@@ -29,23 +36,54 @@ use trace::Trace;
 ///
 /// `Context` may optionally be supplied with a type `T` implementing `Trace`. This "trace object"
 /// is useful for doing things like tracking the progress of long-running operations.
-pub struct Context<'a, T: Trace, S: Store> {
+pub struct Context<'a, T: Trace, S: Store, C: Cipher> {
     repository: &'a mut Repository,
 
     trace: T,
     store: S,
+    cipher: C,
 
     marshal_pool: CpuPool,
+    scheduler: Scheduler,
+    file_scheduler: Scheduler,
 
     marshal_tx: Sender<Hashed>,
     writes: Box<Future<Item = (), Error = Error> + Send>,
 
     index_tx: Sender<(PathBuf, ObjectHash)>,
     index_rx: Receiver<(PathBuf, ObjectHash)>,
+
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    shutdown_rx: oneshot::Receiver<()>,
+}
+
+
+/// A one-shot signal that asks a closing `Context` to stop waiting on its writes and return
+/// promptly. Writes still in flight when it fires are dropped, not drained.
+pub struct Shutdown {
+    tx: oneshot::Sender<()>,
+}
+
+
+impl Shutdown {
+    /// Fire the shutdown signal.
+    pub fn signal(self) {
+        let _ = self.tx.send(());
+    }
+}
+
+
+/// A `Notify` that drops every wakeup. `Context::checkpoint` only needs a task context good
+/// enough to poll `index_rx` once without parking on an empty queue - it re-polls on its own
+/// cadence regardless - so there is nothing useful for a wakeup to do.
+struct NoopNotify;
+
+impl executor::Notify for NoopNotify {
+    fn notify(&self, _id: usize) {}
 }
 
 
-impl<'a, T: Trace, S: Store> Deref for Context<'a, T, S> {
+impl<'a, T: Trace, S: Store, C: Cipher> Deref for Context<'a, T, S, C> {
     type Target = Repository;
 
     fn deref(&self) -> &Self::Target {
@@ -54,14 +92,15 @@ impl<'a, T: Trace, S: Store> Deref for Context<'a, T, S> {
 }
 
 
-impl<'a, T: Trace, S: Store> DerefMut for Context<'a, T, S> {
+impl<'a, T: Trace, S: Store, C: Cipher> DerefMut for Context<'a, T, S, C> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut *self.repository
     }
 }
 
 
-impl<'a, T: Trace + fmt::Debug, S: Store + fmt::Debug> fmt::Debug for Context<'a, T, S> {
+impl<'a, T: Trace + fmt::Debug, S: Store + fmt::Debug, C: Cipher> fmt::Debug
+    for Context<'a, T, S, C> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Context")
             .field("repository", &self.repository)
@@ -74,30 +113,39 @@ impl<'a, T: Trace + fmt::Debug, S: Store + fmt::Debug> fmt::Debug for Context<'a
 }
 
 
-impl<'a, T: Trace, S: Store> Context<'a, T, S> {
-    /// Create a context from a loaded repository, with a supplied trace object.
+impl<'a, T: Trace, S: Store, C: Cipher> Context<'a, T, S, C> {
+    /// Create a context from a loaded repository, with a supplied trace object and a `Cipher`
+    /// controlling encryption-at-rest. Pass `crypto::Plaintext` for the original
+    /// store-plaintext behaviour.
     pub fn new(
         repository: &'a mut Repository,
         trace: T,
         store: S,
+        cipher: C,
         marshal_pool: &CpuPool,
         io_pool: &CpuPool,
     ) -> Self {
         let (marshal_tx, marshal_rx) = mpsc::channel(BATCH_FUTURE_BUFFER_SIZE);
         let (index_tx, index_rx) = mpsc::channel(BATCH_FUTURE_BUFFER_SIZE);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
         let writes = {
             let trace = trace.clone();
             let store = store.clone();
+            let cipher = cipher.clone();
             let writes_unboxed = marshal_rx
-                .map_err(|()| unreachable!("mpsc receivers never error"))
-                .map(move |hashed: Hashed| {
+                .map_err(|()| Error::from_kind(ErrorKind::ShutdownChannel))
+                .and_then(move |hashed: Hashed| {
                     let hash = *hashed.as_hash();
                     let trace = trace.clone();
 
-                    trace.on_write_object_start(&hash);
-                    store.write_object(hashed).map(move |fresh| {
-                        trace.on_write_object_finish(&hash, fresh);
+                    // Seal the object before it reaches the store; the content address stays the
+                    // plaintext hash so deduplication is preserved.
+                    cipher.encrypt(hashed).map(move |sealed| {
+                        trace.on_write_object_start(&hash);
+                        store.write_object(sealed).map(move |fresh| {
+                            trace.on_write_object_finish(&hash, fresh);
+                        })
                     })
                 })
                 .buffer_unordered(WRITE_FUTURE_BUFFER_SIZE)
@@ -111,15 +159,89 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
 
             trace,
             store,
+            cipher,
 
             marshal_pool: marshal_pool.clone(),
+            // Parents (`Commit`, `HashSubtree`) and the `HashFile` children they enqueue and
+            // block on run on separate schedulers, so a parent holding a slot on `scheduler`
+            // never starves the children it is waiting on for a slot on `file_scheduler`.
+            scheduler: Scheduler::new(
+                marshal_pool,
+                BATCH_FUTURE_BUFFER_SIZE,
+                vec![Box::new(MarshalHandler)],
+            ),
+            file_scheduler: Scheduler::new(
+                marshal_pool,
+                BATCH_FUTURE_BUFFER_SIZE,
+                vec![Box::new(FileHandler)],
+            ),
 
             marshal_tx,
             writes,
 
             index_tx,
             index_rx,
+
+            shutdown_tx: Some(shutdown_tx),
+            shutdown_rx,
+        }
+    }
+
+    /// Take the shutdown signal for this context. Firing it during `close()` cuts a hanging
+    /// write short, dropping any writes still in flight. Returns `None` if already taken.
+    pub fn shutdown_signal(&mut self) -> Option<Shutdown> {
+        self.shutdown_tx.take().map(|tx| Shutdown { tx })
+    }
+
+    /// The scheduler coordinating this context's parent marshalling operations (`Commit`,
+    /// `HashSubtree`). Their `HashFile` children run on a separate scheduler so a parent blocked
+    /// on its own children never competes with them for a slot here.
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// The scheduler coordinating this context's leaf `HashFile` operations.
+    pub fn file_scheduler(&self) -> &Scheduler {
+        &self.file_scheduler
+    }
+
+    /// Enqueue a result-bearing unit of work on the scheduler at the given priority, returning a
+    /// future that resolves with the work's result once it is drained and run.
+    ///
+    /// If `cancel` is supplied, it is raced against `work` *before* the work is handed to the
+    /// scheduler, so a token firing after the scheduler has already admitted the task still
+    /// drops it in place rather than merely disconnecting the caller from a task that keeps
+    /// running to completion regardless.
+    fn schedule<F, I>(
+        &self,
+        scheduler: &Scheduler,
+        operation: Operation,
+        priority: Priority,
+        cancel: Option<&CancelToken>,
+        work: F,
+    ) -> Box<Future<Item = I, Error = Error> + Send>
+    where
+        F: Future<Item = I, Error = Error> + Send + 'static,
+        I: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let work: Box<Future<Item = I, Error = Error> + Send> = match cancel {
+            Some(cancel) => cancel.race(work),
+            None => Box::new(work),
+        };
+        let work = work.then(move |result| {
+            let _ = tx.send(result);
+            Ok::<(), Error>(())
+        });
+
+        if let Err(err) = scheduler.enqueue(operation, priority, Box::new(work)) {
+            return Box::new(future::err(err));
         }
+
+        Box::new(rx.then(|result| match result {
+            Ok(inner) => inner,
+            Err(_canceled) => Err(Error::from_kind(ErrorKind::Absurd)),
+        }))
     }
 
     pub fn split_file<P: AsRef<Path>>(
@@ -148,13 +270,131 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
     }
 
     pub fn hash_file<U>(&self, stream: U) -> Box<Future<Item = ObjectHash, Error = Error> + Send>
+    where
+        U: Stream<Item = ArcSlice, Error = Error> + Send + 'static,
+    {
+        self.hash_file_cancellable(stream, Priority::Bulk, None)
+    }
+
+    /// As `hash_file`, but enqueued at `priority`, and if `cancel` is supplied it is raced into
+    /// the scheduled task itself, so firing it drops this file's in-flight hashing rather than
+    /// only the caller's view of it. Used by `commit_future` to run a commit's per-file children
+    /// at the commit's priority and thread its `CancelToken` into each of them.
+    fn hash_file_cancellable<U>(
+        &self,
+        stream: U,
+        priority: Priority,
+        cancel: Option<&CancelToken>,
+    ) -> Box<Future<Item = ObjectHash, Error = Error> + Send>
     where
         U: Stream<Item = ArcSlice, Error = Error> + Send + 'static,
     {
         let marshal_tx = self.marshal_tx.clone();
         let marshaller = Marshaller::with_trace(marshal_tx, self.trace.clone());
 
-        Box::new(self.marshal_pool.spawn(marshaller.process_chunks(stream)))
+        self.schedule(
+            &self.file_scheduler,
+            Operation::HashFile,
+            priority,
+            cancel,
+            marshaller.process_chunks(stream),
+        )
+    }
+
+    /// Fold a chunk's content hash into the `MerkleHash` it contributes as a leaf. `ObjectHash`
+    /// and `MerkleHash` are expected to stay the same width, but that's an invariant of this
+    /// conversion rather than something `MerkleHash::from_bytes` can check on its own, so assert
+    /// it explicitly instead of letting a future width change panic opaquely inside a
+    /// `copy_from_slice`.
+    fn chunk_leaf(hash: &ObjectHash) -> MerkleHash {
+        let bytes = hash.as_bytes();
+        assert_eq!(
+            bytes.len(),
+            MERKLE_HASH_SIZE,
+            "ObjectHash is {} bytes wide, but MerkleHash requires exactly {}",
+            bytes.len(),
+            MERKLE_HASH_SIZE,
+        );
+        MerkleHash::from_bytes(bytes)
+    }
+
+    /// Build the append-only Merkle tree over a file's ordered chunk hashes. Each leaf is folded
+    /// from the chunk's own content-addressed `ObjectHash` rather than a second, independent hash
+    /// of the raw bytes, so a leaf and the address that chunk would be stored under always agree.
+    /// The resulting tree's root commits to the whole file and can be persisted alongside the
+    /// large-object node in the `Store` with [`Context::persist_merkle_tree`].
+    pub fn build_merkle_tree<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<Future<Item = MerkleTree, Error = Error> + Send> {
+        let tree_future = self.split_file(path).fold(MerkleTree::new(), |mut tree, chunk| {
+            let leaf = Self::chunk_leaf(&ObjectHash::hash(chunk.as_ref()));
+            tree.push_leaf(leaf);
+            future::ok::<_, Error>(tree)
+        });
+
+        Box::new(tree_future)
+    }
+
+    /// Seal and write arbitrary bytes to the store directly as a single object, bypassing the
+    /// chunking `Marshaller` pipeline entirely. This is the write-side counterpart to
+    /// `read_object`: both go straight through `cipher` and `store` with nothing in between, so a
+    /// `write_object` followed by a `read_object` of the hash it returns is a true inverse -
+    /// unlike `hash_file`, whose `ObjectHash` addresses a marshalled DAG node rather than the raw
+    /// bytes handed to it.
+    fn write_object(
+        &self,
+        bytes: Vec<u8>,
+    ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+        let hash = ObjectHash::hash(&bytes);
+        let hashed = Hashed::new(bytes);
+        let cipher = self.cipher.clone();
+        let store = self.store.clone();
+
+        let write_future = future::result(cipher.encrypt(hashed))
+            .and_then(move |sealed| store.write_object(sealed).map(move |_fresh| hash));
+
+        Box::new(write_future)
+    }
+
+    /// Persist a tree's leaves as a single raw object via `Context::write_object`, returning the
+    /// `ObjectHash` it is stored under. Going through the raw store write (rather than
+    /// `hash_file`'s chunking `Marshaller`) keeps this the true inverse of the plain `read_object`
+    /// that `prove_chunk` uses to fetch it back, so the bytes `prove_chunk` parses with
+    /// `MerkleTree::from_leaf_bytes` are exactly the bytes `tree.to_bytes()` produced here.
+    pub fn persist_merkle_tree(
+        &self,
+        tree: &MerkleTree,
+    ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+        self.write_object(tree.to_bytes())
+    }
+
+    /// Produce an `O(log n)` inclusion proof that the chunk at `chunk_index` belongs to the
+    /// given file. The proof carries the leaf hash plus the ordered sibling hashes and
+    /// left/right bits along the root path, and can be checked against a published root with
+    /// `merkle::verify_proof`. If the file's tree was already persisted with
+    /// `persist_merkle_tree`, pass its `ObjectHash` as `tree_hash` to fetch it from the store
+    /// instead of rebuilding it from the file.
+    pub fn prove_chunk<P: AsRef<Path>>(
+        &self,
+        path: P,
+        chunk_index: usize,
+        tree_hash: Option<ObjectHash>,
+    ) -> Box<Future<Item = MerkleProof, Error = Error> + Send> {
+        let tree_future: Box<Future<Item = MerkleTree, Error = Error> + Send> = match tree_hash {
+            Some(hash) => Box::new(
+                self.read_object(hash).map(|bytes| MerkleTree::from_leaf_bytes(&bytes)),
+            ),
+            None => self.build_merkle_tree(path),
+        };
+
+        let proof_future = tree_future.and_then(move |tree| {
+            tree.prove(chunk_index).ok_or_else(|| {
+                Error::from_kind(ErrorKind::MerkleIndexOutOfBounds(chunk_index, tree.len()))
+            })
+        });
+
+        Box::new(proof_future)
     }
 
     pub fn hash_subtree<U>(&self, stream: U) -> Box<Future<Item = ObjectHash, Error = Error> + Send>
@@ -173,7 +413,7 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
             })
             .and_then(move |dir_tree| marshaller.process_dir_tree(dir_tree));
 
-        Box::new(self.marshal_pool.spawn(hash_future))
+        self.schedule(&self.scheduler, Operation::HashSubtree, Priority::Normal, None, hash_future)
     }
 
     pub fn hash_commit(
@@ -183,6 +423,69 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
         parents: Vec<ObjectHash>,
         message: String,
         timestamp: DateTime<Utc>,
+    ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+        // Progress isn't observed for a bare `hash_commit`; `spawn_commit` supplies a shared
+        // handle instead. There is nothing to cancel either, so no `CancelToken` is threaded in.
+        let progress = CommitProgress::new(0);
+        self.commit_future(include_opt, exclude_opt, parents, message, timestamp, progress, None)
+    }
+
+    /// Spawn `hash_commit` as a resumable, cancellable job. The returned [`CommitHandle`] lets
+    /// callers `cancel()` in flight - which drops the commit and every per-file hash it admitted
+    /// onto the scheduler, wherever they are actually running - and poll `progress()`. Each
+    /// finished file's `(path, ObjectHash)` is streamed to the on-disk index as it completes;
+    /// call [`Context::checkpoint`] periodically while the job runs to persist them before the
+    /// job finishes, so a restarted commit skips files whose index entry already shows
+    /// `Cached::Hashed`.
+    pub fn spawn_commit(
+        &self,
+        include_opt: Option<&GlobSet>,
+        exclude_opt: Option<&GlobSet>,
+        parents: Vec<ObjectHash>,
+        message: String,
+        timestamp: DateTime<Utc>,
+    ) -> CommitHandle {
+        let pending = self.index.iter()
+            .filter(|&(path, entry)| {
+                let is_included = include_opt
+                    .map(|include| include.is_match(path))
+                    .unwrap_or(false);
+                let is_excluded = exclude_opt
+                    .map(|exclude| exclude.is_match(path))
+                    .unwrap_or(false);
+
+                (is_included || entry.added || entry.tracked) && !is_excluded
+            })
+            .filter(|&(_, entry)| match entry.get() {
+                Some(Cached::Unhashed) | None => true,
+                _ => false,
+            })
+            .count();
+        let progress = CommitProgress::new(pending);
+        let (cancel_tx, cancel_token) = CancelToken::pair();
+
+        let future = self.commit_future(
+            include_opt,
+            exclude_opt,
+            parents,
+            message,
+            timestamp,
+            progress.clone(),
+            Some(cancel_token),
+        );
+
+        CommitHandle::new(progress, cancel_tx, future)
+    }
+
+    fn commit_future(
+        &self,
+        include_opt: Option<&GlobSet>,
+        exclude_opt: Option<&GlobSet>,
+        parents: Vec<ObjectHash>,
+        message: String,
+        timestamp: DateTime<Utc>,
+        progress: Arc<CommitProgress>,
+        cancel: Option<CancelToken>,
     ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
         let marshaller = Marshaller::with_trace(self.marshal_tx.clone(), self.trace.clone());
 
@@ -207,11 +510,20 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
                         // split and hash it.
                         Some(Cached::Unhashed) | None => {
                             let path = path.to_owned();
-                            let chunk_stream = self.split_file(&path);
+                            let progress = progress.clone();
+                            let bytes_progress = progress.clone();
+                            let chunk_stream = self.split_file(&path)
+                                .inspect(move |chunk| bytes_progress.bytes(chunk.len()));
                             let index_tx = self.index_tx.clone();
-                            let hash_future = self.hash_file(chunk_stream);
-
-                            Either::B(hash_future.and_then(|object_hash| {
+                            let hash_future =
+                                self.hash_file_cancellable(
+                                    chunk_stream,
+                                    Priority::Interactive,
+                                    cancel.as_ref(),
+                                );
+
+                            Either::B(hash_future.and_then(move |object_hash| {
+                                progress.file_finished();
                                 index_tx
                                     .send((path.clone(), object_hash))
                                     .map(move |_| (path, Some(object_hash)))
@@ -239,21 +551,106 @@ impl<'a, T: Trace, S: Store> Context<'a, T, S> {
             })
         });
 
-        Box::new(self.marshal_pool.spawn(commit_future))
+        // A commit is interactive work: its per-file children above are enqueued at
+        // `Priority::Interactive` on the file scheduler, so they preempt any queued bulk
+        // re-hashing there.
+        self.schedule(
+            &self.scheduler,
+            Operation::Commit,
+            Priority::Interactive,
+            cancel.as_ref(),
+            commit_future,
+        )
     }
 
     pub fn store(&self) -> &S {
         &self.store
     }
 
+    pub fn cipher(&self) -> &C {
+        &self.cipher
+    }
+
+    /// Fetch an object from the store and decrypt it with the context's cipher, recovering the
+    /// plaintext bytes. This is the symmetric counterpart to the sealing done in the `writes`
+    /// pipeline.
+    pub fn read_object(
+        &self,
+        hash: ObjectHash,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let cipher = self.cipher.clone();
+        let read_future = self.store.read_object(hash).and_then(move |bytes| {
+            cipher.decrypt(&hash, bytes)
+        });
+
+        Box::new(read_future)
+    }
+
+    /// Drain every `(path, ObjectHash)` update already finished hashing and persist it to the
+    /// on-disk index immediately, without waiting for `close()`. Call this periodically while a
+    /// `spawn_commit` job is in flight - e.g. each time `CommitHandle::progress` advances - so a
+    /// crash mid-commit only re-hashes the files finished since the last checkpoint rather than
+    /// the whole commit. Returns the number of entries persisted.
+    ///
+    /// `index_rx` is drained through `executor::spawn`, exactly as `close()` drains it with
+    /// `for_each`, rather than by calling `Stream::poll` directly: a bare `poll()` parks the
+    /// current task on an empty queue, which panics outside of a task context - precisely the
+    /// "call this periodically" usage this method documents. `executor::spawn` supplies a task
+    /// context good for a single poll without requiring a real executor, and an empty queue ends
+    /// the drain with `Async::NotReady` rather than panicking; any update that raced the queue on
+    /// another thread and isn't visible yet simply waits for the next `checkpoint` call or the
+    /// final drain in `close()`, neither of which drops it.
+    pub fn checkpoint(&mut self) -> Result<usize> {
+        let mut persisted = 0;
+        let notify = executor::NotifyHandle::from(Arc::new(NoopNotify));
+        let mut spawned = executor::spawn(&mut self.index_rx);
+
+        loop {
+            match spawned.poll_stream_notify(&notify, 0) {
+                Ok(Async::Ready(Some((path, object_hash)))) => {
+                    self.trace.on_index_cleaned(&path, &object_hash);
+                    self.repository.index.clean(path, object_hash)?;
+                    persisted += 1;
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(()) => return Err(Error::from_kind(ErrorKind::ShutdownChannel)),
+            }
+        }
+
+        Ok(persisted)
+    }
+
     pub fn close(self) -> Box<Future<Item = (), Error = Error> + Send + 'a> {
-        let repository = self.repository;
-        let close_future = self.writes.join(
-            self.index_rx.map_err(|_| Error::from_kind(ErrorKind::Absurd)).for_each(move |(path, object_hash)| {
-                println!("\n\nCleaning entry: ({}, {})\n", path.display(), object_hash);
+        let Context { repository, trace, writes, index_rx, shutdown_rx, .. } = self;
+
+        // Wait for the write side to drain, but let the shutdown signal cut it short. `select`
+        // drops the outstanding writes when the signal fires, abandoning any still in flight, so
+        // `close()` returns promptly instead of hanging. A dropped signal sender simply never
+        // fires, leaving the writes to complete on their own.
+        let shutdown = shutdown_rx.then(|result| match result {
+            Ok(()) => Either::A(future::ok::<(), Error>(())),
+            Err(_canceled) => Either::B(future::empty::<(), Error>()),
+        });
+        let writes = writes
+            .select(shutdown)
+            .map(|((), _)| ())
+            .map_err(|(err, _)| err);
+
+        // Flush every buffered `(path, ObjectHash)` index update, even if the write side failed;
+        // capturing each side's result rather than `join`ing means a write error can't abort
+        // index cleanup. Channel closure is surfaced as a typed error rather than panicking.
+        let index_clean = index_rx
+            .map_err(|()| Error::from_kind(ErrorKind::ShutdownChannel))
+            .for_each(move |(path, object_hash)| {
+                trace.on_index_cleaned(&path, &object_hash);
                 repository.index.clean(path, object_hash)
-            }),
-        ).map(|((), ())| ());
+            });
+
+        let writes = writes.then(Ok::<_, Error>);
+        let index_clean = index_clean.then(Ok::<_, Error>);
+        let close_future = writes
+            .join(index_clean)
+            .and_then(|(writes_result, clean_result)| writes_result.and(clean_result));
 
         Box::new(close_future)
     }
@@ -0,0 +1,189 @@
+//! # `crypto` - convergent encryption for objects at rest.
+//!
+//! A [`Cipher`] sits in the write pipeline between marshalling and the `Store` so that objects
+//! can be encrypted-at-rest without sacrificing content-addressed deduplication. The default
+//! [`Plaintext`] cipher is a no-op; [`Convergent`] implements convergent encryption:
+//!
+//! * the per-object symmetric key is a keyed Blake2b hash of the plaintext (keyed with a
+//!   repo-wide secret), so identical inputs derive identical keys;
+//! * the bytes are sealed with ChaCha20-Poly1305 under a nonce derived from the object hash;
+//! * the content address stays the plaintext `ObjectHash`, so identical inputs still dedupe.
+//!
+//! The per-object key never travels in the clear: it is itself wrapped with ChaCha20-Poly1305
+//! under a repo-wide key-encryption key (KEK) derived from the secret, and only the wrapped key
+//! and both auth tags travel in the object header. Decrypting also re-hashes the recovered
+//! plaintext and checks it against the requested `ObjectHash` before returning it, so a tampered
+//! header can't pass off substituted content under someone else's address.
+
+use blake2::Blake2b;
+use chacha20_poly1305_aead;
+
+use errors::*;
+use marshal::{Hashed, ObjectHash};
+
+
+/// Width of the ChaCha20-Poly1305 key, in bytes.
+const KEY_SIZE: usize = 32;
+
+/// Width of the ChaCha20-Poly1305 nonce, in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// Domain-separation tag mixed into the key-wrapping KEK and its nonce, so neither can collide
+/// with the per-object content key/nonce even though both are derived from the same secret.
+const KEY_WRAP_CONTEXT: &[u8] = b"attaca-convergent-keywrap-v1";
+
+
+/// A transform applied to object bytes on the way into and out of the `Store`. Implementations
+/// must be symmetric: `decrypt(encrypt(x)) == x`.
+pub trait Cipher: Clone + Send + Sync + 'static {
+    /// Seal a marshalled object for storage, keeping its content address (the plaintext
+    /// `ObjectHash`) intact.
+    fn encrypt(&self, hashed: Hashed) -> Result<Hashed>;
+
+    /// Recover the plaintext bytes of a stored object.
+    fn decrypt(&self, hash: &ObjectHash, bytes: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+
+/// The identity cipher: objects are stored as plaintext. This preserves the original behaviour
+/// of the write pipeline.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Plaintext;
+
+
+impl Cipher for Plaintext {
+    fn encrypt(&self, hashed: Hashed) -> Result<Hashed> {
+        Ok(hashed)
+    }
+
+    fn decrypt(&self, _hash: &ObjectHash, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bytes)
+    }
+}
+
+
+/// Convergent encryption keyed by a repo-wide secret. The secret keys the Blake2b derivation so
+/// that two repositories with different secrets produce different ciphertexts for the same
+/// plaintext, while within a single repository identical plaintext still converges.
+#[derive(Clone)]
+pub struct Convergent {
+    secret: Vec<u8>,
+}
+
+
+impl Convergent {
+    /// Build a convergent cipher from a repo-wide secret.
+    pub fn new<B: Into<Vec<u8>>>(secret: B) -> Convergent {
+        Convergent { secret: secret.into() }
+    }
+
+    /// Derive the per-object key as a Blake2b hash of the plaintext, keyed with the repo secret.
+    fn derive_key(&self, plaintext: &[u8]) -> [u8; KEY_SIZE] {
+        let mut digest = Blake2b::new_keyed(&self.secret, KEY_SIZE);
+        digest.process(plaintext);
+
+        let mut key = [0u8; KEY_SIZE];
+        key.copy_from_slice(digest.fixed_result().as_slice());
+        key
+    }
+
+    /// Derive a deterministic nonce from the plaintext object hash. The key already varies per
+    /// object, so a hash-derived nonce keeps encryption convergent while never repeating a
+    /// (key, nonce) pair across distinct objects.
+    fn nonce(hash: &ObjectHash) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&hash.as_bytes()[..NONCE_SIZE]);
+        nonce
+    }
+
+    /// Derive the repo-wide key-encryption-key that wraps each per-object content key before it
+    /// is written to the header. Keeping this separate from `derive_key` means the header never
+    /// carries a key usable on its own - only someone holding `secret` can unwrap it.
+    fn derive_kek(&self) -> [u8; KEY_SIZE] {
+        let mut digest = Blake2b::new_keyed(&self.secret, KEY_SIZE);
+        digest.process(KEY_WRAP_CONTEXT);
+
+        let mut kek = [0u8; KEY_SIZE];
+        kek.copy_from_slice(digest.fixed_result().as_slice());
+        kek
+    }
+
+    /// Derive the nonce used to wrap/unwrap the per-object key. Distinct from `nonce` (via
+    /// `KEY_WRAP_CONTEXT`) so the content cipher and the key-wrapping cipher never reuse a
+    /// (key, nonce) pair even though both are derived from the same object hash.
+    fn wrap_nonce(&self, hash: &ObjectHash) -> [u8; NONCE_SIZE] {
+        let mut digest = Blake2b::new_keyed(&self.secret, NONCE_SIZE);
+        digest.process(KEY_WRAP_CONTEXT);
+        digest.process(hash.as_bytes());
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(digest.fixed_result().as_slice());
+        nonce
+    }
+}
+
+
+impl Cipher for Convergent {
+    fn encrypt(&self, hashed: Hashed) -> Result<Hashed> {
+        let hash = *hashed.as_hash();
+        let plaintext = hashed.as_bytes();
+
+        let key = self.derive_key(plaintext);
+        let nonce = Convergent::nonce(&hash);
+
+        let mut ciphertext = Vec::with_capacity(plaintext.len());
+        let tag = chacha20_poly1305_aead::encrypt(&key, &nonce, &[], plaintext, &mut ciphertext)
+            .chain_err(|| ErrorKind::Encrypt(hash))?;
+
+        // Wrap the per-object key under the repo-wide KEK before it travels in the header;
+        // storing the raw key next to its own ciphertext would let anyone with store access
+        // decrypt trivially. The content address stays the plaintext hash for dedup.
+        let kek = self.derive_kek();
+        let wrap_nonce = self.wrap_nonce(&hash);
+        let mut wrapped_key = Vec::with_capacity(KEY_SIZE);
+        let wrap_tag = chacha20_poly1305_aead::encrypt(&kek, &wrap_nonce, &[], &key, &mut wrapped_key)
+            .chain_err(|| ErrorKind::Encrypt(hash))?;
+
+        Ok(hashed.with_sealed_content(ciphertext, wrapped_key, tag, wrap_tag))
+    }
+
+    fn decrypt(&self, hash: &ObjectHash, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let (header, ciphertext) = Hashed::split_sealed(&bytes)
+            .chain_err(|| ErrorKind::Decrypt(*hash))?;
+
+        // Unwrap the per-object key under the repo-wide KEK. A tampered wrapped key or wrap tag
+        // fails this AEAD check rather than handing back a key we'd otherwise trust.
+        let kek = self.derive_kek();
+        let wrap_nonce = self.wrap_nonce(hash);
+        let mut key = Vec::with_capacity(KEY_SIZE);
+        chacha20_poly1305_aead::decrypt(
+            &kek,
+            &wrap_nonce,
+            &[],
+            &header.key,
+            &header.wrap_tag,
+            &mut key,
+        ).chain_err(|| ErrorKind::Decrypt(*hash))?;
+
+        let nonce = Convergent::nonce(hash);
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        chacha20_poly1305_aead::decrypt(
+            &key,
+            &nonce,
+            &[],
+            ciphertext,
+            &header.tag,
+            &mut plaintext,
+        ).chain_err(|| ErrorKind::Decrypt(*hash))?;
+
+        // The store is content-addressed and every header field above is attacker-controllable;
+        // without this check a tampered header could pass its own AEAD tags while serving
+        // substituted content under someone else's `ObjectHash`.
+        let recovered = ObjectHash::hash(&plaintext);
+        if recovered != *hash {
+            return Err(Error::from_kind(ErrorKind::Decrypt(*hash)));
+        }
+
+        Ok(plaintext)
+    }
+}
@@ -0,0 +1,155 @@
+//! # `job` - interruptible, restartable long-running operations.
+//!
+//! Hashing a commit can touch thousands of files; if the process dies mid-commit all of that
+//! work is otherwise lost. A [`CommitHandle`] turns `hash_commit` into a job that can be
+//! cancelled in flight and that checkpoints its progress: as each file finishes hashing its
+//! `(path, ObjectHash)` is streamed to the on-disk index, where `Context::checkpoint` can persist
+//! it before the job finishes, so a restarted commit skips any file whose index entry already
+//! shows `Cached::Hashed`.
+//!
+//! Cancellation is carried by a [`CancelToken`], not just raced against the handle's own result
+//! receiver: the same token is threaded into every per-file hashing future the scheduler admits
+//! for this job, so firing it drops the in-flight work itself - wherever it happens to be
+//! running - rather than only detaching the caller from a result that keeps computing anyway.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::prelude::*;
+use futures::future::{Either, Shared};
+use futures::sync::oneshot;
+
+use errors::*;
+use marshal::ObjectHash;
+
+
+/// A cloneable cancellation signal for a single commit job. Racing a future against
+/// [`CancelToken::race`] makes it resolve to `ErrorKind::CommitCancelled` - and drops the future
+/// itself, along with anything it owns - as soon as the token fires.
+#[derive(Clone)]
+pub struct CancelToken(Shared<oneshot::Receiver<()>>);
+
+
+impl CancelToken {
+    /// Build a fresh token and the sender that fires it.
+    pub fn pair() -> (oneshot::Sender<()>, CancelToken) {
+        let (tx, rx) = oneshot::channel();
+        (tx, CancelToken(rx.shared()))
+    }
+
+    /// Race `future` against this token. If the token fires first, `future` - and any work it
+    /// holds, including an in-flight hashing future admitted onto the scheduler - is dropped and
+    /// the race resolves to `ErrorKind::CommitCancelled`.
+    pub fn race<F>(&self, future: F) -> Box<Future<Item = F::Item, Error = Error> + Send>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let cancel = self.0.clone();
+        Box::new(future.select2(cancel).then(|result| match result {
+            Ok(Either::A((item, _))) => Ok(item),
+            Err(Either::A((err, _))) => Err(err),
+            Ok(Either::B((_, _))) |
+            Err(Either::B((_, _))) => Err(Error::from_kind(ErrorKind::CommitCancelled)),
+        }))
+    }
+}
+
+
+/// Shared, incrementally-updated progress for a running commit job.
+#[derive(Debug)]
+pub struct CommitProgress {
+    files_total: usize,
+    files_done: AtomicUsize,
+    bytes_hashed: AtomicUsize,
+}
+
+
+impl CommitProgress {
+    pub fn new(files_total: usize) -> Arc<CommitProgress> {
+        Arc::new(CommitProgress {
+            files_total,
+            files_done: AtomicUsize::new(0),
+            bytes_hashed: AtomicUsize::new(0),
+        })
+    }
+
+    /// Record that one more file has finished hashing.
+    pub fn file_finished(&self) {
+        self.files_done.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Record that `len` more bytes have been hashed.
+    pub fn bytes(&self, len: usize) {
+        self.bytes_hashed.fetch_add(len, Ordering::SeqCst);
+    }
+
+    /// Take a consistent-enough snapshot for display.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            files_done: self.files_done.load(Ordering::SeqCst),
+            files_total: self.files_total,
+            bytes_hashed: self.bytes_hashed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+
+/// A point-in-time view of a commit job's progress.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressSnapshot {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_hashed: usize,
+}
+
+
+/// A handle to a spawned commit job. Polling the handle drives the commit to completion;
+/// calling [`CommitHandle::cancel`] fires the [`CancelToken`] that was threaded into every
+/// future the job admitted onto the scheduler - the commit itself and each per-file hash - so
+/// cancellation drops the outstanding work wherever it is actually running, not just the
+/// caller's view of it.
+pub struct CommitHandle {
+    progress: Arc<CommitProgress>,
+    cancel: Option<oneshot::Sender<()>>,
+    future: Box<Future<Item = ObjectHash, Error = Error> + Send>,
+}
+
+
+impl CommitHandle {
+    /// Wrap an already cancellation-aware commit future (built with the `CancelToken` returned
+    /// alongside `cancel`) so it can be observed and cancelled through one handle.
+    pub fn new(
+        progress: Arc<CommitProgress>,
+        cancel: oneshot::Sender<()>,
+        future: Box<Future<Item = ObjectHash, Error = Error> + Send>,
+    ) -> CommitHandle {
+        CommitHandle {
+            progress,
+            cancel: Some(cancel),
+            future,
+        }
+    }
+
+    /// Request cancellation of the running job.
+    pub fn cancel(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+    }
+
+    /// The job's current progress: files done / total and bytes hashed.
+    pub fn progress(&self) -> ProgressSnapshot {
+        self.progress.snapshot()
+    }
+}
+
+
+impl Future for CommitHandle {
+    type Item = ObjectHash;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
@@ -0,0 +1,278 @@
+//! # `merkle` - inclusion proofs over the ordered chunk hashes of a file.
+//!
+//! When a large object is split into chunks and hashed, the chunk hashes are folded into an
+//! append-only binary Merkle tree. The root commits to the whole file, while a [`MerkleProof`]
+//! lets a peer validate that a single chunk belongs to a published file hash in `O(log n)`
+//! without fetching the full object graph - useful for streaming and partial sync.
+//!
+//! The tree is built bottom-up: the chunk hashes form the leaves, each internal node is
+//! `H(left || right)` under a fixed digest, and a trailing unpaired node is carried up to the
+//! next level unchanged rather than duplicated.
+
+use std::fmt;
+
+use sha3::{Digest, Sha3_256};
+
+
+/// The width, in bytes, of a Merkle node hash (SHA3-256).
+pub const MERKLE_HASH_SIZE: usize = 32;
+
+
+/// A node hash in the Merkle tree. Leaves are `H(chunk_bytes)` and internal nodes are
+/// `H(left || right)`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MerkleHash(pub [u8; MERKLE_HASH_SIZE]);
+
+
+impl MerkleHash {
+    /// Hash a leaf's bytes into a `MerkleHash`.
+    pub fn leaf(bytes: &[u8]) -> MerkleHash {
+        let mut digest = Sha3_256::default();
+        digest.input(bytes);
+        MerkleHash::from_digest(digest)
+    }
+
+    /// Combine two child hashes into their parent node hash as `H(left || right)`.
+    pub fn combine(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+        let mut digest = Sha3_256::default();
+        digest.input(&left.0);
+        digest.input(&right.0);
+        MerkleHash::from_digest(digest)
+    }
+
+    fn from_digest(digest: Sha3_256) -> MerkleHash {
+        let mut bytes = [0u8; MERKLE_HASH_SIZE];
+        bytes.copy_from_slice(digest.result().as_slice());
+        MerkleHash(bytes)
+    }
+
+    /// The raw node bytes, for persistence alongside the large-object node in the `Store`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Reconstruct a node hash from its raw bytes.
+    pub fn from_bytes(bytes: &[u8]) -> MerkleHash {
+        let mut inner = [0u8; MERKLE_HASH_SIZE];
+        inner.copy_from_slice(bytes);
+        MerkleHash(inner)
+    }
+}
+
+
+impl fmt::Debug for MerkleHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for MerkleHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+
+/// Which side of its parent a sibling sits on, as seen while walking from a leaf up to the root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+
+/// A single sibling along the path from a proven leaf to the root, tagged with the side it
+/// occupies so the verifier knows which way to concatenate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProofNode {
+    pub side: Side,
+    pub hash: MerkleHash,
+}
+
+
+/// An inclusion proof for a single chunk: the leaf hash plus the ordered sibling hashes (with
+/// left/right bits) along the path to the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub leaf: MerkleHash,
+    pub index: usize,
+    pub siblings: Vec<ProofNode>,
+}
+
+
+/// An append-only binary Merkle tree over a file's ordered chunk hashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+    leaves: Vec<MerkleHash>,
+}
+
+
+impl MerkleTree {
+    /// Create an empty tree.
+    pub fn new() -> MerkleTree {
+        MerkleTree { leaves: Vec::new() }
+    }
+
+    /// Push a chunk's raw bytes as the next leaf.
+    pub fn push_chunk(&mut self, bytes: &[u8]) {
+        self.leaves.push(MerkleHash::leaf(bytes));
+    }
+
+    /// Push an already-computed leaf hash as the next leaf.
+    pub fn push_leaf(&mut self, leaf: MerkleHash) {
+        self.leaves.push(leaf);
+    }
+
+    /// The number of leaves (chunks) committed so far.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Serialize the tree as its ordered, concatenated leaf hashes. Reconstructible with
+    /// [`MerkleTree::from_leaf_bytes`] - this is the form persisted alongside a file's
+    /// large-object node in the `Store`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.leaves.len() * MERKLE_HASH_SIZE);
+        for leaf in &self.leaves {
+            bytes.extend_from_slice(leaf.as_bytes());
+        }
+        bytes
+    }
+
+    /// Reconstruct a tree from the leaf hashes serialized by [`MerkleTree::to_bytes`].
+    pub fn from_leaf_bytes(bytes: &[u8]) -> MerkleTree {
+        let leaves = bytes.chunks(MERKLE_HASH_SIZE).map(MerkleHash::from_bytes).collect();
+        MerkleTree { leaves }
+    }
+
+    /// Fold the current level into its parent level, carrying an unpaired trailing node up
+    /// unchanged.
+    fn fold(level: &[MerkleHash]) -> Vec<MerkleHash> {
+        let mut parents = Vec::with_capacity((level.len() + 1) / 2);
+        let mut pairs = level.chunks(2);
+
+        while let Some(pair) = pairs.next() {
+            match pair {
+                &[ref left, ref right] => parents.push(MerkleHash::combine(left, right)),
+                &[ref last] => parents.push(*last),
+                _ => unreachable!("chunks(2) yields at most two elements"),
+            }
+        }
+
+        parents
+    }
+
+    /// The root hash committing to every pushed leaf. An empty tree has no root.
+    pub fn root(&self) -> Option<MerkleHash> {
+        if self.leaves.is_empty() {
+            return None;
+        }
+
+        let mut level = self.leaves.clone();
+        while level.len() > 1 {
+            level = MerkleTree::fold(&level);
+        }
+
+        Some(level[0])
+    }
+
+    /// Produce an inclusion proof for the chunk at `index`, or `None` if the index is out of
+    /// range.
+    pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaves.len() {
+            return None;
+        }
+
+        let leaf = self.leaves[index];
+        let mut siblings = Vec::new();
+        let mut level = self.leaves.clone();
+        let mut position = index;
+
+        while level.len() > 1 {
+            if position % 2 == 0 {
+                // Left child: the sibling (if any) is to the right. A trailing unpaired node has
+                // no sibling and is carried up unchanged, so we record nothing.
+                if position + 1 < level.len() {
+                    siblings.push(ProofNode {
+                        side: Side::Right,
+                        hash: level[position + 1],
+                    });
+                }
+            } else {
+                siblings.push(ProofNode {
+                    side: Side::Left,
+                    hash: level[position - 1],
+                });
+            }
+
+            level = MerkleTree::fold(&level);
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            leaf,
+            index,
+            siblings,
+        })
+    }
+}
+
+
+impl Default for MerkleTree {
+    fn default() -> MerkleTree {
+        MerkleTree::new()
+    }
+}
+
+
+/// Recompute the root from a leaf at `index`, its ordered siblings, and the claimed root,
+/// returning whether they agree. This is the standalone verifier a peer runs against a file
+/// hash. `index` is checked against the proof - not just carried alongside it - by deriving
+/// the expected left/right side at each level from the leaf's position and rejecting any
+/// `ProofNode` whose `side` disagrees; levels where a trailing unpaired node was carried up
+/// (and so recorded no sibling) are skipped. The verifier is not given the leaf count, so this
+/// does not pin the index down uniquely: bits of `index` above the proof's depth are not
+/// checked, and a proof for a trailing unpaired leaf also verifies at any index that yields the
+/// same sides (with 3 leaves, the proof for index 2 also verifies at index 1).
+pub fn verify_proof(
+    root: &MerkleHash,
+    leaf: &MerkleHash,
+    index: usize,
+    siblings: &[ProofNode],
+) -> bool {
+    let mut node = *leaf;
+    let mut position = index;
+
+    for sibling in siblings {
+        // A left sibling at an even position means the node was carried up unpaired for one or
+        // more levels first; climb to the level where it was finally paired.
+        if sibling.side == Side::Left {
+            while position > 0 && position % 2 == 0 {
+                position /= 2;
+            }
+        }
+
+        let expected_side = if position % 2 == 0 { Side::Right } else { Side::Left };
+        if sibling.side != expected_side {
+            return false;
+        }
+
+        node = match sibling.side {
+            Side::Left => MerkleHash::combine(&sibling.hash, &node),
+            Side::Right => MerkleHash::combine(&node, &sibling.hash),
+        };
+
+        position /= 2;
+    }
+
+    node == *root
+}
@@ -0,0 +1,282 @@
+//! # `scheduler` - prioritized dispatch of repository operations.
+//!
+//! Rather than having every operation call `marshal_pool.spawn` directly - with no
+//! coordination, prioritization or introspection across concurrent work - a [`Scheduler`]
+//! centralizes the plumbing. Heterogeneous units of work are enqueued as [`Operation`]s, each
+//! carrying a [`Priority`]; the scheduler runs them past a list of [`Handler`]s that declare
+//! whether they `accept` a given operation, then drains a priority queue into at most
+//! `max_concurrency` running operations so that, e.g.,
+//! an interactive commit preempts a bulk re-hash. The [`Scheduler::status`] API lists the
+//! queued and running operations for introspection.
+//!
+//! A `Commit`/`HashSubtree` operation enqueues its own `HashFile` children and then blocks
+//! waiting on them. Those children must never compete with their own parent for a concurrency
+//! slot in the same admission queue - a parent parked on its children while holding a slot starves
+//! the queue (an unconditional deadlock at `max_concurrency == 1`, certain starvation once every
+//! slot is held by a blocked parent otherwise). `Context` therefore runs parent operations
+//! (`Commit`, `HashSubtree`, handled by [`MarshalHandler`]) and leaf operations (`HashFile`,
+//! handled by [`FileHandler`]) on two separate `Scheduler`s, each with its own admission count, so
+//! a parent blocked on children can never hold a slot those children need.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use futures_cpupool::CpuPool;
+
+use errors::*;
+
+
+/// The kind of work being scheduled. No current handler accepts `Gc` or `Push`, so enqueuing
+/// either returns `ErrorKind::Unscheduled`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    HashFile,
+    HashSubtree,
+    Commit,
+    Gc,
+    Push,
+}
+
+
+/// Relative urgency of an operation. Interactive work preempts bulk work when both are queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk,
+    Normal,
+    Interactive,
+}
+
+
+/// Whether an operation is waiting in the queue or currently running on the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationState {
+    Queued,
+    Running,
+}
+
+
+/// A snapshot entry for a single operation, as reported by [`Scheduler::status`].
+#[derive(Clone, Copy, Debug)]
+pub struct OperationStatus {
+    pub id: u64,
+    pub operation: Operation,
+    pub priority: Priority,
+    pub state: OperationState,
+}
+
+
+/// A handler declares which operations it is willing to run. The scheduler refuses to enqueue
+/// an operation no handler accepts.
+pub trait Handler: Send + Sync {
+    fn accept(&self, operation: Operation) -> bool;
+}
+
+
+/// A handler that accepts the parent marshalling operations (`HashSubtree`, `Commit`). These
+/// enqueue their own `HashFile` children onto a separate `Scheduler` (see [`FileHandler`]), so
+/// they never compete with their children for a concurrency slot while blocked on them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MarshalHandler;
+
+
+impl Handler for MarshalHandler {
+    fn accept(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::HashSubtree | Operation::Commit => true,
+            Operation::HashFile | Operation::Gc | Operation::Push => false,
+        }
+    }
+}
+
+
+/// A handler that accepts leaf-level hashing work (`HashFile`). Run on its own `Scheduler`,
+/// distinct from the one admitting the `Commit`/`HashSubtree` parents that enqueue these children
+/// and block on them, so a parent holding a parent-pool slot can never starve the children it is
+/// waiting on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileHandler;
+
+
+impl Handler for FileHandler {
+    fn accept(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::HashFile => true,
+            Operation::HashSubtree | Operation::Commit | Operation::Gc | Operation::Push => false,
+        }
+    }
+}
+
+
+struct QueuedTask {
+    id: u64,
+    operation: Operation,
+    priority: Priority,
+    seq: u64,
+    work: Box<Future<Item = (), Error = Error> + Send>,
+}
+
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &QueuedTask) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for QueuedTask {}
+
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &QueuedTask) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedTask {
+    fn cmp(&self, other: &QueuedTask) -> Ordering {
+        // Higher priority first; within a priority, earlier insertions first (FIFO), so the
+        // oldest wins the max-heap via `Reverse` on the sequence number.
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| Reverse(self.seq).cmp(&Reverse(other.seq)))
+    }
+}
+
+
+struct State {
+    queue: BinaryHeap<QueuedTask>,
+    running: usize,
+    next_id: u64,
+    next_seq: u64,
+    status: Vec<OperationStatus>,
+}
+
+
+struct Inner {
+    pool: CpuPool,
+    max_concurrency: usize,
+    state: Mutex<State>,
+}
+
+
+/// A prioritized scheduler owned by the `Context`. Cloning shares the underlying queue and
+/// handler set.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+    handlers: Arc<Vec<Box<Handler>>>,
+}
+
+
+impl Scheduler {
+    /// Build a scheduler running work on `pool`, admitting at most `max_concurrency` operations
+    /// at once, with the given handlers.
+    pub fn new(pool: &CpuPool, max_concurrency: usize, handlers: Vec<Box<Handler>>) -> Scheduler {
+        Scheduler {
+            inner: Arc::new(Inner {
+                pool: pool.clone(),
+                max_concurrency,
+                state: Mutex::new(State {
+                    queue: BinaryHeap::new(),
+                    running: 0,
+                    next_id: 0,
+                    next_seq: 0,
+                    status: Vec::new(),
+                }),
+            }),
+            handlers: Arc::new(handlers),
+        }
+    }
+
+    /// Enqueue a unit of work. Returns `ErrorKind::Unscheduled` if no handler accepts the
+    /// operation. Once admitted the work is drained by priority as pool slots free up.
+    pub fn enqueue(
+        &self,
+        operation: Operation,
+        priority: Priority,
+        work: Box<Future<Item = (), Error = Error> + Send>,
+    ) -> Result<u64> {
+        if !self.handlers.iter().any(|handler| handler.accept(operation)) {
+            return Err(Error::from_kind(ErrorKind::Unscheduled(operation)));
+        }
+
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            let id = state.next_id;
+            let seq = state.next_seq;
+            state.next_id += 1;
+            state.next_seq += 1;
+
+            state.queue.push(QueuedTask {
+                id,
+                operation,
+                priority,
+                seq,
+                work,
+            });
+            state.status.push(OperationStatus {
+                id,
+                operation,
+                priority,
+                state: OperationState::Queued,
+            });
+
+            id
+        };
+
+        Inner::pump(&self.inner);
+
+        Ok(id)
+    }
+
+    /// The queued and running operations, most-urgent first among those queued.
+    pub fn status(&self) -> Vec<OperationStatus> {
+        let mut status = self.inner.state.lock().unwrap().status.clone();
+        // Entries are kept in enqueue order, so a stable sort by descending priority lists the
+        // queued ones in the order `pump` will admit them.
+        status.sort_by(|a, b| b.priority.cmp(&a.priority));
+        status
+    }
+}
+
+
+impl Inner {
+    /// Admit as many queued operations to the pool as the concurrency limit allows.
+    fn pump(inner: &Arc<Inner>) {
+        loop {
+            let task = {
+                let mut state = inner.state.lock().unwrap();
+                if state.running >= inner.max_concurrency {
+                    return;
+                }
+
+                match state.queue.pop() {
+                    Some(task) => {
+                        state.running += 1;
+                        if let Some(entry) =
+                            state.status.iter_mut().find(|entry| entry.id == task.id)
+                        {
+                            entry.state = OperationState::Running;
+                        }
+                        task
+                    }
+                    None => return,
+                }
+            };
+
+            let id = task.id;
+            let inner = inner.clone();
+            inner.pool.clone()
+                .spawn(task.work.then(move |result| {
+                    {
+                        let mut state = inner.state.lock().unwrap();
+                        state.running -= 1;
+                        state.status.retain(|entry| entry.id != id);
+                    }
+                    Inner::pump(&inner);
+                    result
+                }))
+                .forget();
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! # `store::s3` - an S3-compatible object store backend.
+//!
+//! Maps each `ObjectHash` to a key in a bucket so a repository can marshal locally and persist
+//! to cloud storage without a local filesystem store. Writes are conditional `PUT`s (and, for
+//! multipart, a conditional `CompleteMultipartUpload`) under `If-None-Match: *` rather than a
+//! `HEAD` followed by an unconditional `PUT`: the existence check and the write happen
+//! atomically on S3's side, so two concurrent writers of the same content-addressed key can't
+//! both observe "missing" and both report `fresh = true`, double-counting one object. Marshalled
+//! objects over [`S3Config::multipart_threshold`] are streamed with multipart upload: part
+//! bodies are sliced out of the object lazily, one `buffered` window ahead of the uploads that
+//! have actually started, so at most `part_concurrency` parts' worth of bytes are ever duplicated
+//! off the object at once rather than every part being copied out up front. Part uploads are
+//! bounded by that same configurable concurrency, which composes with the
+//! `WRITE_FUTURE_BUFFER_SIZE`-bounded `buffer_unordered` in the write pipeline.
+
+use std::sync::Arc;
+
+use futures::future;
+use futures::prelude::*;
+use futures::stream;
+use rusoto_core::RusotoError;
+use rusoto_core::Region;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, PutObjectRequest, S3,
+    S3Client, UploadPartRequest,
+};
+
+use WRITE_FUTURE_BUFFER_SIZE;
+use errors::*;
+use marshal::{Hashed, ObjectHash};
+use store::Store;
+
+
+/// The smallest part S3 accepts in a multipart upload (5 MiB), used as the part size.
+const MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+
+
+/// Configuration for an [`S3Store`].
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Bucket that objects are written into.
+    pub bucket: String,
+
+    /// Key prefix prepended to each object's hash, e.g. `"objects/"`.
+    pub prefix: String,
+
+    /// Objects at least this large are uploaded with multipart rather than a single `PUT`.
+    pub multipart_threshold: usize,
+
+    /// Maximum number of parts uploaded concurrently for a single object.
+    pub part_concurrency: usize,
+}
+
+
+impl Default for S3Config {
+    fn default() -> S3Config {
+        S3Config {
+            bucket: String::new(),
+            prefix: String::new(),
+            multipart_threshold: MULTIPART_PART_SIZE,
+            part_concurrency: WRITE_FUTURE_BUFFER_SIZE,
+        }
+    }
+}
+
+
+struct Inner {
+    client: S3Client,
+    config: S3Config,
+}
+
+
+/// A `Store` backed by an S3-compatible object store. Cloning shares the underlying client.
+#[derive(Clone)]
+pub struct S3Store {
+    inner: Arc<Inner>,
+}
+
+
+impl S3Store {
+    /// Connect to a bucket in the given region with the default credentials provider.
+    pub fn new(region: Region, config: S3Config) -> S3Store {
+        S3Store {
+            inner: Arc::new(Inner {
+                client: S3Client::new(region),
+                config,
+            }),
+        }
+    }
+
+    /// The object-store key for a hash under the configured prefix.
+    fn key(&self, hash: &ObjectHash) -> String {
+        format!("{}{}", self.inner.config.prefix, hash)
+    }
+
+    /// Upload an object whose size is below the multipart threshold in a single, conditional
+    /// `PUT`. `If-None-Match: *` makes the write itself the existence check: a concurrent writer
+    /// racing this one can't also observe the key missing and also report `fresh = true`, since
+    /// S3 only lets one of the two conditional-on-absence `PUT`s through.
+    fn put(&self, key: String, bytes: Vec<u8>) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let request = PutObjectRequest {
+            bucket: self.inner.config.bucket.clone(),
+            key,
+            body: Some(bytes.into()),
+            if_none_match: Some("*".to_string()),
+            ..Default::default()
+        };
+
+        Box::new(self.inner.client.put_object(request).then(|result| match result {
+            Ok(_) => Ok(true),
+            // The precondition failed: another writer already created this exact
+            // content-addressed key first. The store is content-addressed, so its object is
+            // identical to ours - we just report `fresh = false` instead of clobbering it.
+            Err(ref err) if is_precondition_failed(err) => Ok(false),
+            Err(err) => Err(Error::with_chain(err, ErrorKind::S3Store)),
+        }))
+    }
+
+    /// Upload a large object via multipart, streaming bounded-concurrency part uploads, and
+    /// complete it with the same `If-None-Match: *` precondition `put` uses so the multipart path
+    /// is just as safe under concurrent writers of the same key. Part bodies are sliced out of
+    /// `bytes` lazily as `buffered` pulls ahead of the uploads that have actually started, so at
+    /// most `part_concurrency` parts are ever duplicated off it at once, rather than every part
+    /// being copied out up front.
+    fn put_multipart(
+        &self,
+        key: String,
+        bytes: Arc<Vec<u8>>,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        let bucket = inner.config.bucket.clone();
+        let concurrency = inner.config.part_concurrency;
+        let len = bytes.len();
+        let part_count = (len + MULTIPART_PART_SIZE - 1) / MULTIPART_PART_SIZE;
+
+        let create = CreateMultipartUploadRequest {
+            bucket: bucket.clone(),
+            key: key.clone(),
+            ..Default::default()
+        };
+
+        let upload = inner.client
+            .create_multipart_upload(create)
+            .map_err(|err| Error::with_chain(err, ErrorKind::S3Store))
+            .and_then(move |created| {
+                created.upload_id.ok_or_else(|| Error::from_kind(ErrorKind::S3Store))
+            })
+            .and_then(move |upload_id| {
+                let uploaded = {
+                    let inner = inner.clone();
+                    let bucket = bucket.clone();
+                    let key = key.clone();
+                    let upload_id = upload_id.clone();
+
+                    stream::iter_ok(0..part_count)
+                        .map(move |index| {
+                            let start = index * MULTIPART_PART_SIZE;
+                            let end = ::std::cmp::min(start + MULTIPART_PART_SIZE, len);
+                            let part_number = index as i64 + 1;
+                            let body = bytes[start..end].to_vec();
+
+                            let request = UploadPartRequest {
+                                bucket: bucket.clone(),
+                                key: key.clone(),
+                                upload_id: upload_id.clone(),
+                                part_number,
+                                body: Some(body.into()),
+                                ..Default::default()
+                            };
+
+                            inner.client
+                                .upload_part(request)
+                                .map(move |output| CompletedPart {
+                                    e_tag: output.e_tag,
+                                    part_number: Some(part_number),
+                                })
+                                .map_err(|err| Error::with_chain(err, ErrorKind::S3Store))
+                        })
+                        .buffered(concurrency)
+                        .collect()
+                };
+
+                uploaded.and_then(move |mut completed| {
+                    completed.sort_by_key(|part| part.part_number);
+
+                    let inner = inner.clone();
+                    let bucket_for_abort = bucket.clone();
+                    let key_for_abort = key.clone();
+                    let upload_id_for_abort = upload_id.clone();
+
+                    let request = CompleteMultipartUploadRequest {
+                        bucket,
+                        key,
+                        upload_id,
+                        multipart_upload: Some(CompletedMultipartUpload {
+                            parts: Some(completed),
+                        }),
+                        if_none_match: Some("*".to_string()),
+                        ..Default::default()
+                    };
+
+                    inner.client.complete_multipart_upload(request).then(move |result| {
+                        match result {
+                            Ok(_) => Box::new(future::ok(true))
+                                as Box<Future<Item = bool, Error = Error> + Send>,
+                            // Another writer completed this exact content-addressed key first.
+                            // Abort our now-redundant upload so its parts don't linger as billed,
+                            // unreferenced storage, then report `fresh = false` like `put` does.
+                            Err(ref err) if is_precondition_failed(err) => {
+                                let abort = AbortMultipartUploadRequest {
+                                    bucket: bucket_for_abort,
+                                    key: key_for_abort,
+                                    upload_id: upload_id_for_abort,
+                                    ..Default::default()
+                                };
+
+                                Box::new(inner.client.abort_multipart_upload(abort).then(|_| Ok(false)))
+                            }
+                            Err(err) => Box::new(future::err(Error::with_chain(err, ErrorKind::S3Store))),
+                        }
+                    })
+                })
+            });
+
+        Box::new(upload)
+    }
+}
+
+
+impl Store for S3Store {
+    type Read = Box<Future<Item = Vec<u8>, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let key = self.key(hashed.as_hash());
+        let bytes = hashed.as_bytes().to_vec();
+
+        // `put`/`put_multipart` are conditional on `If-None-Match: *`, so the existence check and
+        // the write happen as one atomic operation on S3's side and `fresh` stays accurate even
+        // under concurrent writers of the same content-addressed key.
+        if bytes.len() >= self.inner.config.multipart_threshold {
+            self.put_multipart(key, Arc::new(bytes))
+        } else {
+            self.put(key, bytes)
+        }
+    }
+
+    fn read_object(&self, hash: ObjectHash) -> Self::Read {
+        let request = GetObjectRequest {
+            bucket: self.inner.config.bucket.clone(),
+            key: self.key(&hash),
+            ..Default::default()
+        };
+
+        let read = self.inner.client
+            .get_object(request)
+            .map_err(|err| Error::with_chain(err, ErrorKind::S3Store))
+            .and_then(|output| {
+                let body = output.body.ok_or_else(|| Error::from_kind(ErrorKind::S3Store))?;
+                Ok(body)
+            })
+            .and_then(|body| {
+                body.concat2()
+                    .map(|bytes| bytes.to_vec())
+                    .map_err(|err| Error::with_chain(err, ErrorKind::S3Store))
+            });
+
+        Box::new(read)
+    }
+}
+
+
+/// Whether a rusoto error carries the given HTTP status. Matching the actual status rather than
+/// the `Debug` rendering avoids misreporting an unrelated error whose text happens to contain the
+/// same digits, or missing a status a provider renders differently.
+fn has_status<E>(err: &RusotoError<E>, status: u16) -> bool {
+    match *err {
+        RusotoError::Unknown(ref response) => response.status.as_u16() == status,
+        _ => false,
+    }
+}
+
+/// Whether a conditional `PUT`/`CompleteMultipartUpload` was rejected (HTTP 412) because its
+/// `If-None-Match: *` precondition failed - i.e. another writer already created this exact
+/// content-addressed key first.
+fn is_precondition_failed<E>(err: &RusotoError<E>) -> bool {
+    has_status(err, 412)
+}